@@ -0,0 +1,86 @@
+use crate::deep_lynx::InitiateDataSourceDownloadQuery;
+use crate::errors::LoaderError;
+use crate::timestamps::{format_timestamp, parse_timestamp, BackfillWindow};
+use crate::DataSourceConfiguration;
+use chrono::{DateTime, Utc};
+
+// one window of a data source's history. Each window is downloaded and committed on its own, along
+// with where the next one starts, so an interrupted backfill picks up from the last finished window.
+// DeepLynx includes rows on the boundary in both windows either side of it, so backfilled sources
+// should set deduplicate if that matters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    pub start: String,
+    // None for the last window, which runs up to the latest data
+    pub end: Option<String>,
+    // windows over an index column rather than a timestamp
+    pub by_index: bool,
+    // where in the secondary index the window starts
+    pub secondary_index_start: u64,
+}
+
+impl Window {
+    // the window of the given size from start, secondary_index_start only being set for the very
+    // first window of a backfill
+    pub fn new(
+        size: BackfillWindow,
+        start: &str,
+        secondary_index_start: u64,
+        now: DateTime<Utc>,
+    ) -> Result<Window, LoaderError> {
+        let invalid = || LoaderError::InvalidTimestamp(start.to_string());
+
+        let (end, by_index) = match size {
+            BackfillWindow::Time(size) => {
+                let from = parse_timestamp(start).ok_or_else(invalid)?;
+
+                // anything reaching past now is the last window, which is left open ended so it
+                // catches everything up to the latest data
+                let end = from
+                    .checked_add_signed(size)
+                    .filter(|end| *end < now)
+                    .map(format_timestamp);
+
+                (end, false)
+            }
+            BackfillWindow::Index(size) => {
+                let from: u64 = start.parse().map_err(|_| invalid())?;
+                (Some(from.saturating_add(size).to_string()), true)
+            }
+        };
+
+        Ok(Window {
+            start: start.to_string(),
+            end,
+            by_index,
+            secondary_index_start,
+        })
+    }
+
+    pub fn query(&self, data_source: &DataSourceConfiguration) -> InitiateDataSourceDownloadQuery {
+        InitiateDataSourceDownloadQuery {
+            start_time: Some(self.start.clone()),
+            end_time: self.end.clone(),
+            secondary_index_name: data_source.secondary_index.clone(),
+            secondary_index_start_value: Some(self.secondary_index_start),
+        }
+    }
+
+    // where the backfill carries on from once this window has been loaded, None meaning it has caught
+    // up and continuous loads can take over from the watermark
+    pub fn next_start(&self, loaded_rows: bool, has_watermark: bool) -> Option<String> {
+        match (&self.end, self.by_index, loaded_rows) {
+            // the last timestamp window had data, so there's a watermark to continue from
+            (None, _, true) => None,
+            // nothing after the start yet, so try the same window next time
+            (None, _, false) => Some(self.start.clone()),
+            (Some(end), false, _) => Some(end.clone()),
+            (Some(end), true, true) => Some(end.clone()),
+            // indexes have no "now" to tell us we've reached the end, so the first empty window is
+            // taken as having caught up - unless nothing's been loaded at all, in which case there's
+            // no watermark to continue from and we try the same window next time
+            (Some(_), true, false) if has_watermark => None,
+            (Some(_), true, false) => Some(self.start.clone()),
+        }
+    }
+}
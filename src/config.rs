@@ -0,0 +1,331 @@
+use crate::errors::LoaderError;
+use crate::retention::parse_size;
+use crate::sql::{check_identifier, TableName};
+use crate::state::STATE_TABLE;
+use crate::timestamps::{initial_start_time, is_index, parse_timezone, BackfillWindow};
+use crate::Configuration;
+use cron::Schedule;
+use log::warn;
+use serde_yaml::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::str::FromStr;
+
+// reads the configuration file, substituting ${NAME} (or ${NAME:-default}) in any string with the
+// environment variable of that name and reading api_key_file and api_secret_file if they're set. The
+// result is validated so that mistakes are caught here rather than halfway through a load
+pub fn load_configuration(path: &str) -> Result<Configuration, LoaderError> {
+    let raw: Value = serde_yaml::from_reader(File::open(path)?)?;
+
+    // misspelled optional fields would otherwise be silently ignored, so note them as we go
+    let mut problems = vec![];
+    let mut config: Configuration = serde_ignored::deserialize(interpolate(raw)?, |field| {
+        problems.push(format!("{field}: unknown field"))
+    })?;
+
+    config.api_key = read_secret(config.api_key, &config.api_key_file, "api_key")?;
+    config.api_secret = read_secret(config.api_secret, &config.api_secret_file, "api_secret")?;
+
+    problems.extend(validate(&config));
+
+    if problems.is_empty() {
+        Ok(config)
+    } else if config.data_sources.is_empty() && problems.len() == 1 {
+        Err(LoaderError::NoDataSources)
+    } else {
+        Err(LoaderError::InvalidConfiguration(problems))
+    }
+}
+
+// everything wrong with the configuration, each prefixed with the path of the field it's about
+pub fn validate(config: &Configuration) -> Vec<String> {
+    let mut problems = vec![];
+
+    match url::Url::parse(&config.deeplynx_url) {
+        Err(e) => problems.push(format!("deeplynx_url: {e}")),
+        Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+            problems.push("deeplynx_url: must be an http or https url".to_string())
+        }
+        Ok(_) if config.deeplynx_url.ends_with('/') => {
+            problems.push("deeplynx_url: must not have a trailing slash".to_string())
+        }
+        Ok(_) => {}
+    }
+
+    if config.data_sources.is_empty() {
+        problems.push(format!("data_sources: {}", LoaderError::NoDataSources));
+    }
+
+    if config.max_concurrency == Some(0) {
+        problems.push("max_concurrency: must be at least 1".to_string());
+    }
+
+    let mut table_names = HashSet::new();
+
+    for (i, data_source) in config.data_sources.iter().enumerate() {
+        let path = format!("data_sources.{i}");
+
+        if let Err(e) = check_table_name(&data_source.table_name) {
+            problems.push(format!("{path}.table_name: {e}"));
+        }
+
+        // duckdb doesn't tell names apart by case, even quoted
+        if !table_names.insert(data_source.table_name.to_lowercase()) {
+            problems.push(format!(
+                "{path}.table_name: {} is used by more than one data source",
+                data_source.table_name
+            ));
+        }
+
+        if let Err(e) = check_identifier(&data_source.timestamp_column_name) {
+            problems.push(format!("{path}.timestamp_column_name: {e}"));
+        }
+
+        if let Some(Err(e)) = data_source.secondary_index.as_deref().map(check_identifier) {
+            problems.push(format!("{path}.secondary_index: {e}"));
+        }
+
+        if let Err(e) =
+            initial_start_time(data_source.initial_timestamp.as_deref(), chrono::Utc::now())
+        {
+            problems.push(format!("{path}.initial_timestamp: {e}"));
+        }
+
+        if let Some(window) = &data_source.backfill_window {
+            let by_index = data_source
+                .initial_timestamp
+                .as_deref()
+                .map(|t| is_index(t.trim()))
+                .unwrap_or(false);
+
+            match (BackfillWindow::from_str(window), by_index) {
+                (Err(e), _) => problems.push(format!("{path}.backfill_window: {e}")),
+                (Ok(BackfillWindow::Time(_)), true) => problems.push(format!(
+                    "{path}.backfill_window: initial_timestamp is an index, so the window must be a number of index values"
+                )),
+                (Ok(BackfillWindow::Index(_)), false) => problems.push(format!(
+                    "{path}.backfill_window: windows of index values need initial_timestamp set to the index to start from"
+                )),
+                _ => {}
+            }
+        }
+
+        if let Err(e) = parse_timezone(data_source.timezone.as_deref()) {
+            problems.push(format!("{path}.timezone: {e}"));
+        }
+
+        if let Some(schedule) = &data_source.schedule {
+            if let Err(e) = Schedule::from_str(schedule) {
+                problems.push(format!("{path}.schedule: {e}"));
+            }
+        }
+
+        if let Some(Err(e)) = data_source
+            .retention_column
+            .as_deref()
+            .map(check_identifier)
+        {
+            problems.push(format!("{path}.retention_column: {e}"));
+        }
+
+        if data_source.max_rows == Some(0) {
+            problems.push(format!("{path}.max_rows: must be at least 1"));
+        }
+
+        if let Some(size) = &data_source.max_table_size {
+            if parse_size(size).is_none() {
+                problems.push(format!(
+                    "{path}.max_table_size: {}",
+                    LoaderError::InvalidSize(size.to_string())
+                ));
+            }
+        }
+
+        if data_source.keep_forever.unwrap_or(false) {
+            let limits = [
+                (
+                    "data_retention_days",
+                    data_source.data_retention_days.is_some(),
+                ),
+                ("max_rows", data_source.max_rows.is_some()),
+                ("max_table_size", data_source.max_table_size.is_some()),
+            ];
+
+            for (field, _) in limits.into_iter().filter(|(_, set)| *set) {
+                problems.push(format!(
+                    "{path}.{field}: can't be combined with keep_forever"
+                ));
+            }
+        }
+
+        if let Some(key_columns) = &data_source.key_columns {
+            if key_columns.is_empty() {
+                problems.push(format!("{path}.key_columns: must not be empty"));
+            }
+
+            for (j, key_column) in key_columns.iter().enumerate() {
+                if let Err(e) = check_identifier(key_column) {
+                    problems.push(format!("{path}.key_columns.{j}: {e}"));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+// table names are quoted wherever they're used, so they can hold spaces, capitals and the like - a
+// single . separates the schema from the table though, as in plant_a.sensor_readings
+fn check_table_name(table_name: &str) -> Result<(), String> {
+    let table = TableName::parse(table_name);
+
+    if table.name.contains('.') {
+        return Err(format!(
+            "{table_name:?} can only be qualified with a schema, as schema.table"
+        ));
+    }
+
+    for part in table.schema.into_iter().chain([table.name]) {
+        check_identifier(part).map_err(|e| format!("{table_name:?}: {e}"))?;
+    }
+
+    if table.name == STATE_TABLE || table.name.to_lowercase().starts_with("_deeplynx_") {
+        return Err(format!(
+            "{table_name:?} is reserved for the loader's own tables"
+        ));
+    }
+
+    Ok(())
+}
+
+// logs a warning if the configuration file holds secrets in plain text and anyone on the machine can
+// read it - called separately from load_configuration as the logger isn't set up until we've loaded
+pub fn warn_if_exposed(path: &str) -> Result<(), LoaderError> {
+    if !world_readable(path)? {
+        return Ok(());
+    }
+
+    let raw: Value = serde_yaml::from_reader(File::open(path)?)?;
+    let exposed: Vec<&str> = ["api_key", "api_secret", "token"]
+        .into_iter()
+        .filter(|field| {
+            let value = match *field {
+                "token" => raw.get("auth").and_then(|auth| auth.get(*field)),
+                _ => raw.get(*field),
+            };
+
+            // values pulled from the environment aren't in the file itself
+            matches!(value, Some(Value::String(s)) if !s.is_empty() && !s.contains("${"))
+        })
+        .collect();
+
+    if !exposed.is_empty() {
+        warn!(
+            "configuration file {path} contains {} and is readable by every user on this machine - restrict its permissions or move the secrets to environment variables or files",
+            exposed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn world_readable(path: &str) -> Result<bool, LoaderError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(fs::metadata(path)?.permissions().mode() & 0o004 != 0)
+}
+
+// windows permissions don't map onto "everyone can read this", so we don't try
+#[cfg(not(unix))]
+fn world_readable(_path: &str) -> Result<bool, LoaderError> {
+    Ok(false)
+}
+
+// walks the whole document so that every string field can be interpolated, not just the ones we
+// thought of
+pub fn interpolate(value: Value) -> Result<Value, LoaderError> {
+    Ok(match value {
+        Value::String(s) => Value::String(expand(&s)?),
+        Value::Sequence(values) => Value::Sequence(
+            values
+                .into_iter()
+                .map(interpolate)
+                .collect::<Result<Vec<Value>, LoaderError>>()?,
+        ),
+        Value::Mapping(mapping) => {
+            let mut interpolated = serde_yaml::Mapping::new();
+
+            for (key, value) in mapping {
+                interpolated.insert(key, interpolate(value)?);
+            }
+
+            Value::Mapping(interpolated)
+        }
+        Value::Tagged(mut tagged) => {
+            tagged.value = interpolate(tagged.value)?;
+            Value::Tagged(tagged)
+        }
+        other => other,
+    })
+}
+
+// replaces each ${NAME} in value with the environment variable NAME, or with default for
+// ${NAME:-default} if NAME isn't set. $${ is left as a literal ${
+pub fn expand(value: &str) -> Result<String, LoaderError> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        expanded.push_str(&rest[..start]);
+
+        let reference = &rest[start + 2..];
+        let end = match reference.find('}') {
+            // not a reference, just some text that happens to contain ${
+            None => {
+                expanded.push_str(&rest[start..]);
+                return Ok(expanded);
+            }
+            Some(end) => end,
+        };
+
+        let (name, default) = match reference[..end].split_once(":-") {
+            None => (&reference[..end], None),
+            Some((name, default)) => (name, Some(default)),
+        };
+
+        match (std::env::var(name), default) {
+            (Ok(v), _) => expanded.push_str(&v),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) => {
+                return Err(LoaderError::MissingEnvironmentVariable(name.to_string()))
+            }
+        }
+
+        rest = &reference[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// a secret can be given directly or as the path to a file holding it, but not both
+fn read_secret(
+    value: Option<String>,
+    file: &Option<String>,
+    field: &str,
+) -> Result<Option<String>, LoaderError> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(LoaderError::ConflictingSecret(field.to_string())),
+        (value, None) => Ok(value),
+        (None, Some(path)) => Ok(Some(fs::read_to_string(path)?.trim().to_string())),
+    }
+}
@@ -13,6 +13,31 @@ pub enum LoaderError {
     Database,
     #[error("no data sources provided")]
     NoDataSources,
+    #[error("background loader is already running")]
+    AlreadyRunning,
+    #[error("background loader thread panicked")]
+    SchedulerPanicked,
+    // every problem found, each prefixed with the path of the field it's about
+    #[error("invalid configuration: {}", .0.join("; "))]
+    InvalidConfiguration(Vec<String>),
+    #[error(
+        "invalid timestamp {0}, expected something like -7d, 2024-01-01T00:00:00Z or 2024-01-01"
+    )]
+    InvalidTimestamp(String),
+    #[error("invalid backfill window {0}, expected a duration like 1d or 6h, or a number of index values")]
+    InvalidBackfillWindow(String),
+    #[error("unknown timezone {0}, expected an IANA name like America/Denver or UTC")]
+    InvalidTimezone(String),
+    #[error("invalid size {0}, expected something like 500MB or 2GB")]
+    InvalidSize(String),
+    #[error("no data source loads into table {0}")]
+    UnknownTable(String),
+    #[error("invalid backfill range: {0}")]
+    InvalidBackfillRange(String),
+    #[error("environment variable {0} referenced in the configuration isn't set")]
+    MissingEnvironmentVariable(String),
+    #[error("{0} and {0}_file can't both be set")]
+    ConflictingSecret(String),
     #[error("io error {0}")]
     IO(#[from] io::Error),
     #[error("duckdb underlying error: {0}")]
@@ -25,6 +50,24 @@ pub enum LoaderError {
     YAMLParsing(#[from] serde_yaml::Error),
     #[error("number parsing error: {0}")]
     NumberParsing(#[from] std::num::ParseIntError),
+    #[error(
+        "not enough disk space at {path}: {required} bytes required, {available} bytes available"
+    )]
+    InsufficientDiskSpace {
+        path: String,
+        required: u64,
+        available: u64,
+    },
+    #[error("md5 of downloaded file {file_id} was {actual}, expected {expected}")]
+    ChecksumMismatch {
+        file_id: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("schema of table {0} changed: {1}")]
+    SchemaChanged(String, String),
+    #[error("invalid schedule: {0}")]
+    Schedule(#[from] cron::error::Error),
     #[error("blob conversion error, THIS SHOULD NEVER HAPPEN")]
     BlobConversion(#[from] std::str::Utf8Error),
 }
@@ -1,26 +1,58 @@
+mod backfill;
+mod config;
 mod deep_lynx;
 mod errors;
+mod report;
+mod retention;
+mod scheduler;
+mod schema;
+mod sql;
+mod state;
 mod tests;
+mod timestamps;
 
-use crate::deep_lynx::{DeepLynxAPI, InitiateDataSourceDownloadQuery};
+use crate::backfill::Window;
+use crate::config::{load_configuration, warn_if_exposed};
+use crate::deep_lynx::{
+    AuthConfiguration, DeepLynxAPI, HttpConfiguration, InitiateDataSourceDownloadQuery, RetryPolicy,
+};
 use crate::errors::LoaderError;
-use chrono::NaiveDateTime;
-use duckdb::types::{TimeUnit, Value};
+use crate::report::{LoadReport, SourceFailure, Stage};
+use crate::scheduler::Scheduler;
+use crate::schema::{Reconciled, SchemaChangePolicy};
+use crate::sql::{quote_identifier, quote_literal, TableName};
+use crate::state::Watermark;
+use crate::timestamps::{
+    format_watermark_date, format_watermark_time, format_watermark_timestamp, initial_start_time,
+    parse_timestamp, parse_timezone, resolve_timestamp, BackfillWindow,
+};
+use chrono_tz::Tz;
+use duckdb::types::Value;
 use duckdb::{AccessMode, Config, OptionalExt, Row};
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use md5::{Digest, Md5};
 use pyo3::prelude::*;
 use serde::{Deserialize, Serialize};
-use serde_yaml::from_reader;
 use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fs, io, thread};
 use uuid::Uuid;
 
+// how many data sources are downloaded at once if max_concurrency isn't set
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+// how many times a download that fails md5 verification is attempted before giving up
+const DEFAULT_DOWNLOAD_ATTEMPTS: u32 = 3;
+
 #[pyclass]
 #[derive(Debug, Clone)]
 pub struct Loader {
     config: Configuration,
     client: DeepLynxAPI,
+    // handle to the background refresh thread if start() has been called - shared so that clones of
+    // the loader all see the same running state
+    scheduler: Arc<Mutex<Option<Scheduler>>>,
     // you might ask why we don't hold the duckdb connection open - that's because we really don't
     // want to hold a rw connection open while the python code runs, in case they want to use it for
     // something
@@ -30,6 +62,9 @@ pub struct Loader {
 pub struct Configuration {
     api_key: Option<String>,
     api_secret: Option<String>,
+    // paths to files holding the api key and secret, instead of putting them in the configuration
+    api_key_file: Option<String>,
+    api_secret_file: Option<String>,
     deeplynx_url: String,
     db_path: String,
     refresh_interval: u64,
@@ -37,11 +72,31 @@ pub struct Configuration {
     target_data_source_id: Option<u64>,
     target_container_id: Option<u64>,
     debug: Option<bool>,
+    // where downloads are written before being loaded, defaults to the system temp directory
+    temp_dir: Option<String>,
+    // timeouts, proxy and headers for requests to DeepLynx
+    http: Option<HttpConfiguration>,
+    // how failed requests to DeepLynx are retried
+    retry: Option<RetryPolicy>,
+    // where tokens come from, defaults to exchanging api_key and api_secret
+    auth: Option<AuthConfiguration>,
+    // how long DeepLynx tokens are requested for, defaults to 12h
+    token_expiry: Option<String>,
+    // how many seconds before a token expires to fetch a new one, defaults to 60
+    token_refresh_margin_secs: Option<u64>,
+    // how many times to try a download that fails md5 verification, defaults to 3
+    download_attempts: Option<u32>,
+    // how many data sources can be downloading at the same time, defaults to 4
+    max_concurrency: Option<usize>,
+    // what to do when DeepLynx adds, removes or retypes columns, defaults to add
+    schema_change_policy: Option<SchemaChangePolicy>,
     data_sources: Vec<DataSourceConfiguration>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DataSourceConfiguration {
+    // older versions of the readme called this name
+    #[serde(alias = "name")]
     table_name: String,
     container_id: u64,
     data_source_id: u64,
@@ -49,12 +104,43 @@ pub struct DataSourceConfiguration {
     secondary_index: Option<String>,
     initial_timestamp: Option<String>,
     initial_index_start: Option<u64>,
+    // overrides the global refresh_interval for this data source, in seconds
+    refresh_interval: Option<u64>,
+    // cron expression (including a seconds field) for when to fetch this data source, takes
+    // precedence over refresh_interval
+    schedule: Option<String>,
+    // overrides the global schema_change_policy for this data source
+    schema_change_policy: Option<SchemaChangePolicy>,
+    // replace existing rows with the same key instead of appending duplicates
+    deduplicate: Option<bool>,
+    // the columns that uniquely identify a row when deduplicating, defaults to the timestamp column
+    // and secondary index
+    key_columns: Option<Vec<String>>,
+    // load history a window at a time rather than in one go, e.g 1d - or a number of index values if
+    // the timestamp column is an index
+    backfill_window: Option<String>,
+    // IANA timezone (e.g America/Denver) that TIMESTAMP columns without a time zone are in, defaults
+    // to UTC
+    timezone: Option<String>,
+    // overrides the global data_retention_days for this data source
+    data_retention_days: Option<u32>,
+    // the column data_retention_days is measured against, for data sources whose timestamp column is
+    // an index - without it only max_rows and max_table_size apply to them
+    retention_column: Option<String>,
+    // the most rows to keep, the oldest are removed first
+    max_rows: Option<u64>,
+    // the most space the table should take up in the database file, e.g 500MB
+    max_table_size: Option<String>,
+    // never remove anything from the table, ignoring all of the above
+    keep_forever: Option<bool>,
 }
 
 /// A Python module implemented in Rust.
 #[pymodule]
 fn deeplynx_loader(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Loader>()?;
+    m.add_class::<LoadReport>()?;
+    m.add_class::<SourceFailure>()?;
     Ok(())
 }
 
@@ -62,8 +148,7 @@ fn deeplynx_loader(_py: Python, m: &PyModule) -> PyResult<()> {
 impl Loader {
     #[new]
     pub fn new(config_file_path: &str) -> Result<Self, LoaderError> {
-        let config_file = File::open(config_file_path)?;
-        let config: Configuration = from_reader(config_file)?;
+        let config = load_configuration(config_file_path)?;
 
         let mut log_level = log::LevelFilter::Error;
 
@@ -87,31 +172,95 @@ impl Loader {
             .chain(fern::log_file("deeplynx_loader.log")?)
             .apply()?;
 
-        let mut client = DeepLynxAPI::new(
-            config.deeplynx_url.clone(),
-            config.api_key.clone(),
-            config.api_secret.clone(),
-        )?;
+        warn_if_exposed(config_file_path)?;
+
+        let client = new_client(&config)?;
 
-        Ok(Loader { config, client })
+        Ok(Loader {
+            config,
+            client,
+            scheduler: Arc::new(Mutex::new(None)),
+        })
     }
 
-    pub fn load_data(&self) -> Result<(), LoaderError> {
-        // open the duckdb connection outside the thread so we can indicate failure if needed
-        let db_path = Path::new(self.config.db_path.as_str());
-        let conn = duckdb::Connection::open_with_flags(
-            db_path,
-            Config::default().access_mode(AccessMode::ReadWrite)?,
-        )?;
+    // fetches the latest data for every data source. A data source failing doesn't stop the others
+    // from loading - check the returned report's failures to see what went wrong
+    pub fn load_data(&self, py: Python<'_>) -> Result<LoadReport, LoaderError> {
+        // release the GIL while we talk to DeepLynx and DuckDB so other python threads can run
+        py.allow_threads(|| load_once(&self.config, &self.client))
+    }
+
+    // spawns a background thread running the load loop every refresh_interval seconds, the thread
+    // is a native thread so it never holds the GIL
+    pub fn start(&self) -> Result<(), LoaderError> {
+        let mut scheduler = self
+            .scheduler
+            .lock()
+            .map_err(|_| LoaderError::SchedulerPanicked)?;
+
+        if scheduler.is_some() {
+            return Err(LoaderError::AlreadyRunning);
+        }
+
+        *scheduler = Some(Scheduler::start(self.config.clone(), self.client.clone())?);
+        Ok(())
+    }
 
-        load_loop(&self.config, &conn)?;
+    // stops the background thread, blocking until any in-flight fetch has finished - calling this
+    // when the loader isn't running is a no-op
+    pub fn stop(&self, py: Python<'_>) -> Result<(), LoaderError> {
+        let scheduler = self
+            .scheduler
+            .lock()
+            .map_err(|_| LoaderError::SchedulerPanicked)?
+            .take();
 
-        match conn.close() {
-            Ok(_) => Ok(()),
-            Err(ce) => Err(LoaderError::DuckDB(ce.1)),
+        match scheduler {
+            None => Ok(()),
+            Some(s) => py.allow_threads(|| s.stop()),
         }
     }
 
+    pub fn is_running(&self) -> Result<bool, LoaderError> {
+        Ok(self
+            .scheduler
+            .lock()
+            .map_err(|_| LoaderError::SchedulerPanicked)?
+            .is_some())
+    }
+
+    // downloads exactly the range from start to end for a data source and merges it into its table,
+    // replacing any rows that are already there - for recovering data that's been cleaned up or
+    // damaged. start and end take the same forms as initial_timestamp. This doesn't change where
+    // regular loads carry on from
+    pub fn backfill(
+        &self,
+        py: Python<'_>,
+        table_name: &str,
+        start: &str,
+        end: &str,
+    ) -> Result<(), LoaderError> {
+        let data_source = self
+            .config
+            .data_sources
+            .iter()
+            .find(|data_source| data_source.table_name == table_name)
+            .ok_or_else(|| LoaderError::UnknownTable(table_name.to_string()))?;
+
+        let plan = FetchPlan::Range(range_query(data_source, start, end)?);
+
+        py.allow_threads(|| {
+            // download before opening the database so we don't hold it open any longer than we need
+            let downloaded = download(&self.config, data_source, &self.client, plan.query())?;
+
+            let conn = open_connection(&self.config)?;
+            let loaded = load_download(&self.config, data_source, &plan, downloaded, &conn);
+            close_connection(conn)?;
+
+            loaded
+        })
+    }
+
     pub fn send_file(&mut self, file_path: &str, data_source_id: u64) -> Result<(), LoaderError> {
         self.client.import(
             self.config
@@ -126,99 +275,485 @@ impl Loader {
     }
 }
 
-fn load_loop(config: &Configuration, conn: &duckdb::Connection) -> Result<(), LoaderError> {
+// runs a single pass of the load loop against a freshly opened connection, closing it afterwards
+// so we don't hold the database open between runs
+fn load_once(config: &Configuration, client: &DeepLynxAPI) -> Result<LoadReport, LoaderError> {
+    let conn = open_connection(config)?;
+    let report = load_loop(config, client, &conn);
+    close_connection(conn)?;
+
+    Ok(report)
+}
+
+fn open_connection(config: &Configuration) -> Result<duckdb::Connection, LoaderError> {
+    let db_path = Path::new(config.db_path.as_str());
+    Ok(duckdb::Connection::open_with_flags(
+        db_path,
+        Config::default().access_mode(AccessMode::ReadWrite)?,
+    )?)
+}
+
+fn close_connection(conn: duckdb::Connection) -> Result<(), LoaderError> {
+    match conn.close() {
+        Ok(_) => Ok(()),
+        Err(ce) => Err(LoaderError::DuckDB(ce.1)),
+    }
+}
+
+// an open transaction on a connection that rolls back when dropped unless it's been committed, so
+// returning early with ? never leaves half of a load applied
+struct Transaction<'a> {
+    conn: &'a duckdb::Connection,
+    committed: bool,
+}
+
+impl<'a> Transaction<'a> {
+    fn begin(conn: &'a duckdb::Connection) -> Result<Transaction<'a>, LoaderError> {
+        conn.execute_batch("BEGIN TRANSACTION")?;
+
+        Ok(Transaction {
+            conn,
+            committed: false,
+        })
+    }
+
+    fn commit(mut self) -> Result<(), LoaderError> {
+        self.conn.execute_batch("COMMIT")?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.conn.execute_batch("ROLLBACK") {
+                error!("unable to roll back transaction: {e}");
+            }
+        }
+    }
+}
+
+// runs a single pass over every data source. Downloads happen on a bounded pool of worker threads so
+// one slow source doesn't hold up the rest, but every read and write against the database happens
+// here on the calling thread so the connection is only ever used by one thing at a time. A failing
+// data source is recorded in the report and skipped, it never stops the others from loading
+fn load_loop(
+    config: &Configuration,
+    client: &DeepLynxAPI,
+    conn: &duckdb::Connection,
+) -> LoadReport {
+    let mut report = LoadReport::default();
+
+    // working out where each source should start from only touches the database, so do it up front
+    let mut plans: Vec<(&DataSourceConfiguration, FetchPlan)> = vec![];
     for data_source in &config.data_sources {
-        let mut client = DeepLynxAPI::new(
-            config.deeplynx_url.clone(),
-            config.api_key.clone(),
-            config.api_secret.clone(),
-        )?;
-        // we could run this check just once on startup instead of checking each time, but this is more robust
-        // and we have no idea what kind of SQL the other users might be running on it - changes how
-        // we load data in
-        let table_exists: Option<String> = conn
-            .query_row(
-                "SELECT table_name FROM duckdb_tables() WHERE table_name = ?",
-                [data_source.table_name.clone()],
-                |row| row.get(0),
-            )
-            .optional()?;
+        match plan_fetch(data_source, conn) {
+            Ok(plan) => plans.push((data_source, plan)),
+            Err(e) => report.failed(data_source, Stage::Plan, e),
+        }
+    }
+
+    // sources that are backfilling come back with their next window until they've caught up
+    while !plans.is_empty() {
+        plans = load_round(config, client, conn, plans, &mut report);
+    }
+
+    for failure in &report.failures {
+        error!("{failure}");
+    }
+
+    report
+}
+
+// downloads and loads each of the plans, returning the next window of any backfill that moved forward
+fn load_round<'a>(
+    config: &Configuration,
+    client: &DeepLynxAPI,
+    conn: &duckdb::Connection,
+    plans: Vec<(&'a DataSourceConfiguration, FetchPlan)>,
+    report: &mut LoadReport,
+) -> Vec<(&'a DataSourceConfiguration, FetchPlan)> {
+    let mut next_round = vec![];
+
+    let workers = max_concurrency(config).min(plans.len());
+    let queue = Mutex::new(plans.into_iter());
+    let (sender, downloads) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let queue = &queue;
+
+            scope.spawn(move || loop {
+                let next = match queue.lock() {
+                    Ok(mut queue) => queue.next(),
+                    Err(_) => None,
+                };
+
+                let (data_source, plan) = match next {
+                    None => break,
+                    Some(next) => next,
+                };
+
+                let downloaded = download(config, data_source, client, plan.query());
+
+                if sender.send((data_source, plan, downloaded)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        // drop our copy of the sender so the loop below ends once every worker has finished
+        drop(sender);
+
+        for (data_source, plan, downloaded) in downloads {
+            let downloaded = match downloaded {
+                Ok(downloaded) => downloaded,
+                Err(e) => {
+                    report.failed(data_source, Stage::Download, e);
+                    continue;
+                }
+            };
+
+            // a failed load is rolled back, so the connection is still fine to use for the rest
+            if let Err(e) = load_download(config, data_source, &plan, downloaded, conn) {
+                report.failed(data_source, Stage::Load, e);
+                continue;
+            }
+
+            if let FetchPlan::Backfill(..) = plan {
+                match plan_fetch(data_source, conn) {
+                    Ok(next) if next.continues(&plan) => {
+                        next_round.push((data_source, next));
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        report.failed(data_source, Stage::Plan, e);
+                        continue;
+                    }
+                }
+            }
+
+            report.succeeded(data_source);
+        }
+    });
+
+    next_round
+}
+
+fn new_client(config: &Configuration) -> Result<DeepLynxAPI, LoaderError> {
+    Ok(DeepLynxAPI::new(
+        config.deeplynx_url.clone(),
+        config.api_key.clone(),
+        config.api_secret.clone(),
+    )?
+    .with_retry_policy(config.retry.clone().unwrap_or_default())
+    .with_auth(config.auth.clone().unwrap_or_default())
+    .with_token_lifetime(
+        config.token_expiry.clone(),
+        config.token_refresh_margin_secs,
+    )
+    .with_http_configuration(config.http.clone().unwrap_or_default())?)
+}
+
+fn max_concurrency(config: &Configuration) -> usize {
+    config
+        .max_concurrency
+        .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+        .max(1)
+}
+
+// what needs to happen for a data source on this run, worked out from what's already in the
+// database before we ever talk to DeepLynx
+#[derive(Debug, Clone)]
+pub enum FetchPlan {
+    // the table doesn't exist or has never been loaded, so it will be (re)created from the download
+    Initial(InitiateDataSourceDownloadQuery),
+    // the table has been loaded before, so only what's newer than the last watermark gets appended
+    Continuous(InitiateDataSourceDownloadQuery),
+    // the data source is still working through its history a window at a time, the table is created
+    // by the first window with any data
+    Backfill(Window, InitiateDataSourceDownloadQuery),
+    // a specific range asked for through Loader.backfill, merged into the table without moving the
+    // watermark
+    Range(InitiateDataSourceDownloadQuery),
+}
+
+impl FetchPlan {
+    pub fn query(&self) -> InitiateDataSourceDownloadQuery {
+        match self {
+            FetchPlan::Initial(q) => q.clone(),
+            FetchPlan::Continuous(q) => q.clone(),
+            FetchPlan::Backfill(_, q) => q.clone(),
+            FetchPlan::Range(q) => q.clone(),
+        }
+    }
+
+    // true if this plan is the next window of previous's backfill, i.e the last window moved the
+    // backfill forward and there's more history to load before it catches up
+    pub fn continues(&self, previous: &FetchPlan) -> bool {
+        match (previous, self) {
+            (FetchPlan::Backfill(previous, _), FetchPlan::Backfill(next, _)) => {
+                previous.start != next.start
+            }
+            _ => false,
+        }
+    }
+}
+
+// a data source download sitting on local disk, waiting to be loaded. The file is removed when this
+// is dropped, so it gets cleaned up no matter where a download or load fails
+#[derive(Debug)]
+pub struct Download {
+    pub path: PathBuf,
+    pub file_id: String,
+}
 
-        // if we don't have a table, treat this is as an initial fetch so the table gets created
-        if table_exists.is_none() {
+impl Download {
+    // the path as a quoted sql string literal, for handing to read_csv_auto
+    fn sql_path(&self) -> String {
+        quote_literal(&self.path.display().to_string())
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            // the file might never have been created if the download failed early
+            if e.kind() != io::ErrorKind::NotFound {
+                error!(
+                    "unable to remove temporary file {}: {e}",
+                    self.path.display()
+                );
+            }
+        }
+    }
+}
+
+pub fn plan_fetch(
+    data_source: &DataSourceConfiguration,
+    conn: &duckdb::Connection,
+) -> Result<FetchPlan, LoaderError> {
+    let state = state::load_state(conn, &data_source.table_name)?;
+    let exists = table_exists(&data_source.table_name, conn)?;
+
+    // if we don't have a table there's nothing to resume from, so treat this is as an initial fetch
+    // so the table gets created. Otherwise resume from wherever we recorded the last load finishing -
+    // tables loaded before the state table existed won't have an entry, so fall back to inferring it
+    // from the last row
+    let watermark = match &state {
+        _ if !exists => None,
+        Some(s) if s.watermark.timestamp.is_some() => Some(s.watermark.clone()),
+        _ => last_record_watermark(data_source, conn)?,
+    };
+
+    if let Some(size) = backfill_window(data_source)? {
+        // a table that's been dropped since it was last loaded starts over
+        let dropped = !exists
+            && state
+                .as_ref()
+                .map(|s| s.watermark.timestamp.is_some())
+                .unwrap_or(false);
+        let until = state
+            .as_ref()
+            .and_then(|s| s.backfill_until.clone())
+            .filter(|_| !dropped);
+
+        let window = match (until, &watermark) {
+            // carry on from the last window we finished
+            (Some(until), _) => Some((until, 0)),
+            // never loaded (or the table's gone), start from the beginning
+            (None, None) => Some((
+                initial_start_time(data_source.initial_timestamp.as_deref(), chrono::Utc::now())?,
+                data_source.initial_index_start.unwrap_or(0),
+            )),
+            // the backfill has caught up
+            (None, Some(_)) => None,
+        };
+
+        if let Some((start, secondary_index_start)) = window {
+            let window = Window::new(size, &start, secondary_index_start, chrono::Utc::now())?;
             debug!(
-                "table {} does not exist, running initial fetch",
-                data_source.table_name.clone()
+                "backfilling table {} from {} to {}",
+                data_source.table_name,
+                window.start,
+                window.end.as_deref().unwrap_or("now")
             );
-            initial_fetch_and_load(config, data_source, &mut client, conn)?;
-            continue;
+
+            let query = window.query(data_source);
+            return Ok(FetchPlan::Backfill(window, query));
         }
+    }
 
-        debug!(
-            "table {} exists, running continual fetch",
-            data_source.table_name.clone()
-        );
-        continuous_fetch_and_load(config, data_source, &mut client, conn)?;
+    match watermark {
+        None => {
+            debug!(
+                "table {} does not exist or is empty, running initial fetch",
+                data_source.table_name
+            );
+            Ok(FetchPlan::Initial(initial_query(data_source)?))
+        }
+        Some(watermark) => {
+            debug!(
+                "table {} exists, running continual fetch",
+                data_source.table_name
+            );
+            Ok(FetchPlan::Continuous(continuous_query(
+                data_source,
+                &watermark,
+            )))
+        }
+    }
+}
 
-        // run the data clean functionality
-        clean_data(config, data_source, conn)?;
+fn backfill_window(
+    data_source: &DataSourceConfiguration,
+) -> Result<Option<BackfillWindow>, LoaderError> {
+    data_source
+        .backfill_window
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+}
+
+// we could run this check just once on startup instead of checking each time, but this is more robust
+// and we have no idea what kind of SQL the other users might be running on it - changes how
+// we load data in
+fn table_exists(table_name: &str, conn: &duckdb::Connection) -> Result<bool, LoaderError> {
+    let table = TableName::parse(table_name);
+    let table: Option<String> = conn
+        .query_row(
+            "SELECT table_name FROM duckdb_tables() WHERE table_name = ? AND schema_name = coalesce(?, schema_name)",
+            duckdb::params![table.name, table.schema],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(table.is_some())
+}
+
+// builds the download query for a data source's first load, starting from initial_timestamp (a day
+// ago if it isn't set) and initial_index_start
+fn initial_query(
+    data_source: &DataSourceConfiguration,
+) -> Result<InitiateDataSourceDownloadQuery, LoaderError> {
+    Ok(InitiateDataSourceDownloadQuery {
+        start_time: Some(initial_start_time(
+            data_source.initial_timestamp.as_deref(),
+            chrono::Utc::now(),
+        )?),
+        end_time: None, // deeplynx defaults to latest timestamp if no endpoint is provided
+        secondary_index_name: data_source.secondary_index.clone(),
+        secondary_index_start_value: Some(data_source.initial_index_start.unwrap_or(0)),
+    })
+}
+
+// builds the download query for exactly the range from start to end
+fn range_query(
+    data_source: &DataSourceConfiguration,
+    start: &str,
+    end: &str,
+) -> Result<InitiateDataSourceDownloadQuery, LoaderError> {
+    let now = chrono::Utc::now();
+    let start = resolve_timestamp(start, now)?;
+    let end = resolve_timestamp(end, now)?;
+
+    let backwards = match (parse_timestamp(&start), parse_timestamp(&end)) {
+        (Some(s), Some(e)) => s > e,
+        _ => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(s), Ok(e)) => s > e,
+            // one's an index and the other a timestamp
+            _ => {
+                return Err(LoaderError::InvalidBackfillRange(format!(
+                    "{start} and {end} must both be timestamps or both be indexes"
+                )))
+            }
+        },
+    };
+
+    if backwards {
+        return Err(LoaderError::InvalidBackfillRange(format!(
+            "{start} is after {end}"
+        )));
     }
 
-    Ok(())
+    Ok(InitiateDataSourceDownloadQuery {
+        start_time: Some(start),
+        end_time: Some(end),
+        secondary_index_name: data_source.secondary_index.clone(),
+        secondary_index_start_value: Some(0),
+    })
 }
 
-pub fn clean_data(
-    config: &Configuration,
+// builds the download query for everything from the watermark onwards
+fn continuous_query(
+    data_source: &DataSourceConfiguration,
+    watermark: &Watermark,
+) -> InitiateDataSourceDownloadQuery {
+    InitiateDataSourceDownloadQuery {
+        start_time: watermark.timestamp.clone(),
+        end_time: None, // deeplynx defaults to latest timestamp if no endpoint is provided
+        secondary_index_name: data_source.secondary_index.clone(),
+        secondary_index_start_value: match watermark.secondary_index {
+            None => Some(0),
+            Some(i) => Some(i),
+        },
+    }
+}
+
+// finds the latest record in the user's table, or None if the table is empty - only used for tables
+// that don't have an entry in the state table yet
+fn last_record_watermark(
     data_source: &DataSourceConfiguration,
     conn: &duckdb::Connection,
-) -> Result<(), LoaderError> {
-    conn.execute(
-        format!(
-            "DELETE FROM {} WHERE {} < NOW() - interval '{}' days",
-            data_source.table_name, data_source.timestamp_column_name, config.data_retention_days
-        )
-        .as_str(),
-        [],
-    )?;
-    Ok(())
+) -> Result<Option<Watermark>, LoaderError> {
+    latest_watermark(data_source, data_source.table_name.as_str(), conn)
 }
 
-// if data or table already exists for a data source, then we fetch continuously
-pub fn continuous_fetch_and_load(
-    config: &Configuration,
+// finds the latest record in a staged download, or None if it has no rows
+fn staged_watermark(
     data_source: &DataSourceConfiguration,
-    client: &mut DeepLynxAPI,
+    staging_table: &str,
     conn: &duckdb::Connection,
-) -> Result<(), LoaderError> {
-    // we need to fetch the last record in the table, but the sort isn't guaranteed so we'll do that
-    // manually
+) -> Result<Option<Watermark>, LoaderError> {
+    latest_watermark(data_source, staging_table, conn)
+}
+
+// pulls the latest timestamp/index and secondary index out of anything we can select from
+fn latest_watermark(
+    data_source: &DataSourceConfiguration,
+    from: &str,
+    conn: &duckdb::Connection,
+) -> Result<Option<Watermark>, LoaderError> {
+    let timestamp_column = quote_identifier(&data_source.timestamp_column_name);
+    let from = TableName::parse(from);
+
+    // we need to fetch the last record, but the sort isn't guaranteed so we'll do that manually
     let mut check_query = format!(
-        "SELECT {} FROM {} ORDER BY {} DESC LIMIT 1",
-        data_source.timestamp_column_name,
-        data_source.table_name,
-        data_source.timestamp_column_name
+        "SELECT {timestamp_column},typeof({timestamp_column}) FROM {from} ORDER BY {timestamp_column} DESC LIMIT 1"
     );
 
     // sort by secondary index as well if it exists, get the latest value
     if (data_source.secondary_index.is_some()) {
-        let secondary_index = data_source
-            .secondary_index
-            .clone()
-            .ok_or(LoaderError::UnwrapOption)?;
+        let secondary_index = quote_identifier(
+            data_source
+                .secondary_index
+                .as_deref()
+                .ok_or(LoaderError::UnwrapOption)?,
+        );
 
         check_query = format!(
-            "SELECT {},{} FROM {} ORDER BY {} DESC,{} DESC LIMIT 1",
-            data_source.timestamp_column_name,
-            secondary_index,
-            data_source.table_name,
-            data_source.timestamp_column_name,
-            secondary_index
+            "SELECT {timestamp_column},typeof({timestamp_column}),{secondary_index} FROM {from} ORDER BY {timestamp_column} DESC,{secondary_index} DESC LIMIT 1"
         );
     }
 
     // simple struct representing the record from the DB
     struct Record {
-        timestamp_or_index: duckdb::types::Value, // the api expects a string even if it's an index number
+        timestamp_or_index: duckdb::types::Value,
+        type_name: String,
         secondary_index: Option<u64>,
     }
 
@@ -227,26 +762,39 @@ pub fn continuous_fetch_and_load(
         .query_row(check_query.as_str(), [], |row: &Row| {
             let mut secondary_index: Option<u64> = None;
             if data_source.secondary_index.is_some() {
-                secondary_index = Some(row.get(1)?);
+                secondary_index = Some(row.get(2)?);
             }
 
             Ok(Record {
                 timestamp_or_index: row.get(0)?,
+                type_name: row.get(1)?,
                 secondary_index,
             })
         })
         .optional()?;
 
-    // if we don't have a last record, we need to drop the table and run initial fetch and load again
-    if last_record.is_none() {
-        return initial_fetch_and_load(config, data_source, client, conn);
+    match last_record {
+        None => Ok(None),
+        Some(r) => Ok(Some(Watermark {
+            timestamp: watermark_string(
+                r.timestamp_or_index,
+                &r.type_name,
+                parse_timezone(data_source.timezone.as_deref())?,
+            )?,
+            secondary_index: r.secondary_index,
+        })),
     }
+}
 
-    let last_record = last_record.ok_or(LoaderError::UnwrapOption)?;
-
-    // because we need to handle either an index or timestamp we have to match through duckdb's type
-    // and convert to what we need - super fun!
-    let start_time: Option<String> = match last_record.timestamp_or_index {
+// because we need to handle either an index or timestamp we have to match through duckdb's type
+// and convert to what the api expects - super fun! type_name is what duckdb's typeof() gave for the
+// value, as TIMESTAMP and TIMESTAMP WITH TIME ZONE come back as the same Value
+fn watermark_string(
+    value: Value,
+    type_name: &str,
+    timezone: Tz,
+) -> Result<Option<String>, LoaderError> {
+    Ok(match value {
         Value::Null => None,
         Value::Boolean(b) => Some(b.to_string()),
         Value::TinyInt(t) => Some(t.to_string()),
@@ -261,167 +809,425 @@ pub fn continuous_fetch_and_load(
         Value::Float(f) => Some(f.to_string()),
         Value::Double(d) => Some(d.to_string()),
         Value::Decimal(d) => Some(d.to_string()),
-        Value::Timestamp(unit, v) => match unit {
-            TimeUnit::Second => Some(
-                NaiveDateTime::from_timestamp_opt(v, 0)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-            TimeUnit::Millisecond => Some(
-                NaiveDateTime::from_timestamp_millis(v)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-
-            TimeUnit::Microsecond => Some(
-                NaiveDateTime::from_timestamp_micros(v)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-            TimeUnit::Nanosecond => None, // we can't parse out nanos - thankfully they shouldn't come this way
-        },
+        Value::Timestamp(unit, v) => Some(
+            format_watermark_timestamp(
+                unit,
+                v,
+                type_name.eq_ignore_ascii_case("TIMESTAMP WITH TIME ZONE"),
+                timezone,
+            )
+            .ok_or(LoaderError::Database)?,
+        ),
         Value::Text(s) => Some(s),
         Value::Blob(v) => Some(std::str::from_utf8(v.as_slice())?.to_string()),
-        Value::Date32(d) => Some(d.to_string()),
-        Value::Time64(unit, v) => match unit {
-            TimeUnit::Second => Some(
-                NaiveDateTime::from_timestamp_opt(v, 0)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-            TimeUnit::Millisecond => Some(
-                NaiveDateTime::from_timestamp_millis(v)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-
-            TimeUnit::Microsecond => Some(
-                NaiveDateTime::from_timestamp_micros(v)
-                    .ok_or(LoaderError::Database)?
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string(),
-            ),
-            TimeUnit::Nanosecond => None, // we can't parse out nanos - thankfully they shouldn't come this way
-        },
-    };
+        Value::Date32(d) => Some(format_watermark_date(d).ok_or(LoaderError::Database)?),
+        Value::Time64(unit, v) => {
+            Some(format_watermark_time(unit, v).ok_or(LoaderError::Database)?)
+        }
+    })
+}
 
+// downloads the data source to a csv file in the temp directory, touching only the network and never
+// the database so that it's safe to run on a worker thread
+pub fn download(
+    config: &Configuration,
+    data_source: &DataSourceConfiguration,
+    client: &DeepLynxAPI,
+    query: InitiateDataSourceDownloadQuery,
+) -> Result<Download, LoaderError> {
     // first we fetch the file pointer for the download, this way we can check filesize against disk
     // passing in the elements provided by the user, if none provided will default to returning
     // the full table currently
     let file_pointer = client.initiate_data_source_download(
         data_source.container_id,
         data_source.data_source_id,
-        InitiateDataSourceDownloadQuery {
-            start_time,
-            end_time: None, // deeplynx defaults to latest timestamp if no endpoint is provided
-            secondary_index_name: data_source.secondary_index.clone(),
-            secondary_index_start_value: match last_record.secondary_index {
-                None => Some(0),
-                Some(i) => Some(i),
-            },
-        },
+        query,
     )?;
 
-    // TODO: check file against disk size prior to downloading
+    let dir = temp_dir(config);
+    fs::create_dir_all(&dir)?;
 
-    let mut file_stream =
-        client.download_file(data_source.container_id, file_pointer.id.parse()?, true)?;
+    // don't start a download we don't have room for, the next run will check again
+    check_disk_space(config, file_pointer.file_size)?;
 
-    // copy the file stream from the download to a temporary file
-    let uuid = Uuid::new_v4();
-    let mut file = File::create(format!("{uuid}.csv"))?;
-    io::copy(&mut file_stream, &mut file)?;
+    let file_id: u64 = file_pointer.id.parse()?;
+    let attempts = config
+        .download_attempts
+        .unwrap_or(DEFAULT_DOWNLOAD_ATTEMPTS)
+        .max(1);
+    let mut attempt = 1;
 
-    // create a table from that .csv and load it in duckdb
-    let inserted = conn.execute(
-        format!(
-            "COPY {} FROM '{uuid}.csv' (HEADER TRUE)",
-            data_source.table_name
-        )
-        .as_str(),
-        [],
-    )?;
+    // DeepLynx only keeps the file around until it's deleted, so we don't ask for it to be deleted
+    // after download - if the copy turns out to be truncated or corrupt we need to be able to fetch
+    // it again
+    let downloaded = loop {
+        // create the guard before we write anything so a failed copy still cleans up after itself
+        let downloaded = Download {
+            path: dir.join(format!(
+                "deeplynx_{}_{}.csv",
+                data_source.table_name,
+                Uuid::new_v4().simple()
+            )),
+            file_id: file_pointer.id.clone(),
+        };
 
-    if inserted == 0 {
-        debug!(
-            "no data inserted for data source {} on continuous fetch, continuing loop",
-            data_source.data_source_id
+        let mut file_stream = client.download_file(data_source.container_id, file_id, false)?;
+
+        // copy the file stream from the download to a temporary file, hashing as we go
+        let mut file = HashingWriter::new(File::create(&downloaded.path)?);
+        io::copy(&mut file_stream, &mut file)?;
+        let md5hash = file.finish();
+
+        // older versions of DeepLynx don't send a hash, nothing we can check against then
+        if file_pointer.md5hash.is_empty() || file_pointer.md5hash.eq_ignore_ascii_case(&md5hash) {
+            break downloaded;
+        }
+
+        if attempt >= attempts {
+            return Err(LoaderError::ChecksumMismatch {
+                file_id: file_pointer.id,
+                expected: file_pointer.md5hash,
+                actual: md5hash,
+            });
+        }
+
+        warn!(
+            "download of file {} for table {} failed md5 verification (attempt {attempt} of {attempts}), retrying",
+            file_pointer.id, data_source.table_name
+        );
+        attempt += 1;
+    };
+
+    // now that we have a verified copy it's safe to remove the file from DeepLynx - failing to do so
+    // only leaves an extra file on the server, so it's not worth failing the load over
+    if let Err(e) = client.delete_file(data_source.container_id, file_id) {
+        warn!(
+            "unable to delete downloaded file {} from DeepLynx: {e}",
+            file_pointer.id
         );
     }
 
-    fs::remove_file(format!("{uuid}.csv"))?;
+    Ok(downloaded)
+}
+
+// passes writes through to the inner writer while keeping a running md5 hash of everything written
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Md5::new(),
+        }
+    }
+
+    // the lowercase hex digest of everything written
+    fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// makes sure there's room for a file of file_size bytes both where it's downloaded to and on the
+// volume the database lives on, since loading it will grow the database by up to the same amount
+fn check_disk_space(config: &Configuration, file_size: f64) -> Result<(), LoaderError> {
+    let required = file_size.max(0.0).ceil() as u64;
+
+    for dir in [temp_dir(config), db_dir(config)] {
+        let available = fs2::available_space(&dir)?;
+
+        if available < required {
+            return Err(LoaderError::InsufficientDiskSpace {
+                path: dir.display().to_string(),
+                required,
+                available,
+            });
+        }
+    }
+
     Ok(())
 }
 
-// the first loading call for a data source, ensures a table is created if data exists - this is a
-// DESTRUCTIVE operation as it will first wipe the table if it exists to insure that the table matches
-// the latest data from the source
-pub fn initial_fetch_and_load(
+// the directory the database file lives in
+fn db_dir(config: &Configuration) -> PathBuf {
+    match Path::new(config.db_path.as_str()).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// where downloads are staged before being loaded, defaults to the system temp directory
+fn temp_dir(config: &Configuration) -> PathBuf {
+    match &config.temp_dir {
+        None => std::env::temp_dir(),
+        Some(dir) => PathBuf::from(dir),
+    }
+}
+
+// loads a downloaded file into the database according to the plan it was downloaded for, then
+// records the new watermark so the next run knows where to pick up from. Every step - schema changes,
+// inserts, cleaning and the state update - happens in a single transaction, so if anything fails part
+// way through the table is left exactly as it was
+pub fn load_download(
     config: &Configuration,
     data_source: &DataSourceConfiguration,
-    client: &mut DeepLynxAPI,
+    plan: &FetchPlan,
+    downloaded: Download,
     conn: &duckdb::Connection,
 ) -> Result<(), LoaderError> {
-    // first drop the table if it exists so that we can guarantee its the right structure
+    let transaction = Transaction::begin(conn)?;
+
+    // everything gets loaded into a temporary table first so that we can compare its columns against
+    // the existing table before touching it
+    let staging_table = format!("_deeplynx_staging_{}", Uuid::new_v4().simple());
     conn.execute(
-        format!("DROP TABLE IF EXISTS {}", data_source.table_name).as_str(),
+        format!(
+            "CREATE TEMP TABLE {staging_table} AS SELECT * FROM read_csv_auto({}, header=true)",
+            downloaded.sql_path()
+        )
+        .as_str(),
         [],
     )?;
 
-    // first we fetch the file pointer for the download, this way we can check filesize against disk
-    // passing in the elements provided by the user, if none provided will default to returning
-    // the full table currently
-    let file_pointer = client.initiate_data_source_download(
-        data_source.container_id,
-        data_source.data_source_id,
-        InitiateDataSourceDownloadQuery {
-            start_time: data_source.initial_timestamp.clone(),
-            end_time: None, // deeplynx defaults to latest timestamp if no endpoint is provided
-            secondary_index_name: data_source.secondary_index.clone(),
-            secondary_index_start_value: Some(0),
-        },
+    load_staged(
+        config,
+        data_source,
+        plan,
+        &downloaded.file_id,
+        &staging_table,
+        conn,
     )?;
 
-    // TODO: check file against disk size prior to downloading
-
-    let mut file_stream =
-        client.download_file(data_source.container_id, file_pointer.id.parse()?, true)?;
-
-    // copy the file stream from the download to a temporary file
-    let uuid = Uuid::new_v4();
-    let mut file = File::create(format!("{uuid}.csv"))?;
-    io::copy(&mut file_stream, &mut file)?;
+    conn.execute(format!("DROP TABLE {staging_table}").as_str(), [])?;
+    transaction.commit()
+}
 
-    // create a table from that .csv and load it in duckdb
-    let inserted = conn.execute(
-        format!(
-            "CREATE TABLE {} AS SELECT * FROM '{uuid}.csv'",
-            data_source.table_name
-        )
-        .as_str(),
+fn load_staged(
+    config: &Configuration,
+    data_source: &DataSourceConfiguration,
+    plan: &FetchPlan,
+    file_id: &str,
+    staging_table: &str,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    let table_name = data_source.table_name.as_str();
+    let table = TableName::parse(table_name);
+    let rows: i64 = conn.query_row(
+        format!("SELECT count(*) FROM {staging_table}").as_str(),
         [],
+        |row| row.get(0),
     )?;
 
-    // if no rows are inserted we need to remove the table as the inference of the data types might
-    // be incorrect, when the process loops again it will attempt to create the table again if it
-    // doesn't already exist - don't error out because lack of data doesn't constitute an error state
-    // but do log it
-    if inserted == 0 {
+    // if nothing came back on an initial fetch we don't create the table, as the inference of the
+    // data types from an empty file might be incorrect - when the process loops again it will attempt
+    // to create the table again. Don't error out because lack of data doesn't constitute an error
+    // state but do log it
+    if rows == 0 {
         debug!(
-            "no data fetched for data source {}, dropping temporary table",
+            "no data fetched for data source {}, continuing loop",
             data_source.data_source_id
         );
+
+        return match plan {
+            FetchPlan::Initial(_) => state::clear_state(conn, table_name),
+            FetchPlan::Continuous(_) => {
+                state::save_state(conn, data_source, None, file_id)?;
+                retention::clean_data(config, data_source, conn)
+            }
+            FetchPlan::Range(_) => Ok(()),
+            FetchPlan::Backfill(window, _) => {
+                let has_watermark = state::load_state(conn, table_name)?
+                    .map(|s| s.watermark.timestamp.is_some())
+                    .unwrap_or(false);
+
+                state::save_backfill_progress(
+                    conn,
+                    data_source,
+                    window.next_start(false, has_watermark).as_deref(),
+                )
+            }
+        };
+    }
+
+    if !table_exists(table_name, conn)? {
+        create_table(table, staging_table, conn)?;
+    } else {
+        let policy = match data_source
+            .schema_change_policy
+            .or(config.schema_change_policy)
+            .unwrap_or_default()
+        {
+            // a requested range is only ever merged in, dropping the table for it would lose
+            // everything else
+            SchemaChangePolicy::Rebuild if matches!(plan, FetchPlan::Range(_)) => {
+                SchemaChangePolicy::Fail
+            }
+            policy => policy,
+        };
+
+        match schema::reconcile(conn, table_name, staging_table, policy)? {
+            Reconciled::Insert { columns, select } => {
+                // a requested range is likely to overlap what's already there, so it always replaces
+                // existing rows rather than duplicating them
+                if data_source.deduplicate.unwrap_or(false) || matches!(plan, FetchPlan::Range(_)) {
+                    merge_staged(data_source, staging_table, conn)?;
+                }
+
+                conn.execute(
+                    format!(
+                        "INSERT INTO {table} ({}) SELECT {} FROM {staging_table}",
+                        columns.join(", "),
+                        select.join(", ")
+                    )
+                    .as_str(),
+                    [],
+                )?;
+            }
+            // continuous downloads and backfill windows only hold some of the rows, so rebuilding
+            // from them would lose everything else - instead forget the table entirely and let the
+            // next run fetch it again from the start
+            Reconciled::Rebuild => {
+                conn.execute(format!("DROP TABLE {table}").as_str(), [])?;
+
+                match plan {
+                    FetchPlan::Initial(_) => create_table(table, staging_table, conn)?,
+                    FetchPlan::Continuous(_) | FetchPlan::Backfill(..) | FetchPlan::Range(_) => {
+                        return state::clear_state(conn, table_name)
+                    }
+                }
+            }
+        }
+    }
+
+    // a requested range is usually well behind where regular loads are, so it mustn't move them
+    if let FetchPlan::Range(_) = plan {
+        return Ok(());
+    }
+
+    let watermark = staged_watermark(data_source, staging_table, conn)?;
+    state::save_state(conn, data_source, watermark.as_ref(), file_id)?;
+
+    match plan {
+        // run the data clean functionality
+        FetchPlan::Continuous(_) => retention::clean_data(config, data_source, conn),
+        FetchPlan::Backfill(window, _) => state::save_backfill_progress(
+            conn,
+            data_source,
+            window.next_start(true, true).as_deref(),
+        ),
+        FetchPlan::Initial(_) | FetchPlan::Range(_) => Ok(()),
+    }
+}
+
+// creates a data source's table from a staged download, along with its schema if it's qualified with
+// one that doesn't exist yet
+fn create_table(
+    table: TableName,
+    staging_table: &str,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    if let Some(schema) = table.schema {
         conn.execute(
-            format!("DROP TABLE IF EXISTS {}", data_source.table_name).as_str(),
+            format!("CREATE SCHEMA IF NOT EXISTS {}", quote_identifier(schema)).as_str(),
             [],
         )?;
     }
 
-    fs::remove_file(format!("{uuid}.csv"))?;
+    conn.execute(
+        format!("CREATE TABLE {table} AS SELECT * FROM {staging_table}").as_str(),
+        [],
+    )?;
+    Ok(())
+}
+
+// removes any rows from the table that the staged download is about to replace, matching on the
+// key columns. Continuous fetches start from the last timestamp inclusive, so without this the
+// boundary rows would be inserted again on every run
+fn merge_staged(
+    data_source: &DataSourceConfiguration,
+    staging_table: &str,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    let table_name = data_source.table_name.as_str();
+    // aliased, as a schema qualified table can't be used to qualify its columns
+    let matches: Vec<String> = key_columns(data_source)
+        .iter()
+        .map(|key| {
+            let key = quote_identifier(key);
+            format!("target.{key} IS NOT DISTINCT FROM staged.{key}")
+        })
+        .collect();
+
+    let replaced = conn.execute(
+        format!(
+            "DELETE FROM {} AS target USING {staging_table} AS staged WHERE {}",
+            TableName::parse(table_name),
+            matches.join(" AND ")
+        )
+        .as_str(),
+        [],
+    )?;
+
+    if replaced > 0 {
+        debug!("replacing {replaced} existing rows in table {table_name}");
+    }
+
     Ok(())
 }
+
+// the columns that uniquely identify a row - the timestamp column and secondary index unless the
+// user has told us otherwise
+fn key_columns(data_source: &DataSourceConfiguration) -> Vec<String> {
+    if let Some(keys) = &data_source.key_columns {
+        return keys.clone();
+    }
+
+    let mut keys = vec![data_source.timestamp_column_name.clone()];
+    if let Some(secondary_index) = &data_source.secondary_index {
+        keys.push(secondary_index.clone());
+    }
+
+    keys
+}
+
+// if data or table already exists for a data source, then we fetch continuously
+pub fn continuous_fetch_and_load(
+    config: &Configuration,
+    data_source: &DataSourceConfiguration,
+    client: &DeepLynxAPI,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    let plan = plan_fetch(data_source, conn)?;
+    let downloaded = download(config, data_source, client, plan.query())?;
+
+    load_download(config, data_source, &plan, downloaded, conn)
+}
+
+// the first loading call for a data source, ensures a table is created if data exists. If the table
+// already exists the download is appended to it, reconciling its columns according to the configured
+// schema_change_policy
+pub fn initial_fetch_and_load(
+    config: &Configuration,
+    data_source: &DataSourceConfiguration,
+    client: &DeepLynxAPI,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    let plan = FetchPlan::Initial(initial_query(data_source)?);
+    let downloaded = download(config, data_source, client, plan.query())?;
+
+    load_download(config, data_source, &plan, downloaded, conn)
+}
@@ -0,0 +1,117 @@
+use crate::errors::LoaderError;
+use crate::DataSourceConfiguration;
+use pyo3::prelude::*;
+use std::fmt;
+
+// the part of loading a data source that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // working out where to resume from
+    Plan,
+    // talking to DeepLynx and writing the file to disk
+    Download,
+    // loading the file into the database
+    Load,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Plan => write!(f, "plan"),
+            Stage::Download => write!(f, "download"),
+            Stage::Load => write!(f, "load"),
+        }
+    }
+}
+
+// a data source that failed to load - other data sources carry on regardless
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFailure {
+    #[pyo3(get)]
+    pub table_name: String,
+    #[pyo3(get)]
+    pub data_source_id: u64,
+    #[pyo3(get)]
+    pub stage: String,
+    #[pyo3(get)]
+    pub error: String,
+}
+
+impl SourceFailure {
+    pub fn new(
+        data_source: &DataSourceConfiguration,
+        stage: Stage,
+        error: LoaderError,
+    ) -> SourceFailure {
+        SourceFailure {
+            table_name: data_source.table_name.clone(),
+            data_source_id: data_source.data_source_id,
+            stage: stage.to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SourceFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "table {} (data source {}) failed during {}: {}",
+            self.table_name, self.data_source_id, self.stage, self.error
+        )
+    }
+}
+
+#[pymethods]
+impl SourceFailure {
+    fn __repr__(&self) -> String {
+        self.to_string()
+    }
+}
+
+// the outcome of a pass over the data sources, returned to python from load_data
+#[pyclass]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    // tables that were loaded successfully
+    #[pyo3(get)]
+    pub loaded: Vec<String>,
+    #[pyo3(get)]
+    pub failures: Vec<SourceFailure>,
+}
+
+impl LoadReport {
+    pub fn succeeded(&mut self, data_source: &DataSourceConfiguration) {
+        self.loaded.push(data_source.table_name.clone());
+    }
+
+    pub fn failed(
+        &mut self,
+        data_source: &DataSourceConfiguration,
+        stage: Stage,
+        error: LoaderError,
+    ) {
+        self.failures
+            .push(SourceFailure::new(data_source, stage, error));
+    }
+}
+
+#[pymethods]
+impl LoadReport {
+    // true if every data source loaded successfully
+    #[getter]
+    fn ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn __repr__(&self) -> String {
+        let failures: Vec<String> = self.failures.iter().map(|f| f.to_string()).collect();
+
+        format!(
+            "LoadReport(loaded=[{}], failures=[{}])",
+            self.loaded.join(", "),
+            failures.join("; ")
+        )
+    }
+}
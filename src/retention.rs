@@ -0,0 +1,187 @@
+use crate::errors::LoaderError;
+use crate::schema::table_columns;
+use crate::sql::{quote_identifier, quote_literal, TableName};
+use crate::{Configuration, DataSourceConfiguration};
+use log::{debug, warn};
+
+// duckdb writes tables to the database file in blocks of this size
+const BLOCK_SIZE: u128 = 262_144;
+
+// trims a data source's table after a continuous load. Rows older than data_retention_days (the data
+// source's own, or the global one) are removed, then the oldest rows beyond max_rows or
+// max_table_size - unless the data source is kept forever
+pub fn clean_data(
+    config: &Configuration,
+    data_source: &DataSourceConfiguration,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    if data_source.keep_forever.unwrap_or(false) {
+        return Ok(());
+    }
+
+    let table = TableName::parse(&data_source.table_name);
+    let retention_days = data_source
+        .data_retention_days
+        .unwrap_or(config.data_retention_days);
+
+    match age_column(data_source, conn)? {
+        Some(column) => {
+            let removed = conn.execute(
+                format!(
+                    "DELETE FROM {table} WHERE {} < NOW() - interval '{retention_days}' days",
+                    quote_identifier(column)
+                )
+                .as_str(),
+                [],
+            )?;
+
+            if removed > 0 {
+                debug!("removed {removed} rows older than {retention_days} days from table {table}");
+            }
+        }
+        None => debug!(
+            "table {table} has no timestamp column to apply data_retention_days to, only row and size limits apply"
+        ),
+    }
+
+    let size_limit = match &data_source.max_table_size {
+        None => None,
+        Some(size) => match parse_size(size) {
+            None => return Err(LoaderError::InvalidSize(size.to_string())),
+            Some(max_bytes) => rows_within_size(table, max_bytes, conn)?,
+        },
+    };
+
+    if let Some(max_rows) = [data_source.max_rows, size_limit]
+        .into_iter()
+        .flatten()
+        .min()
+    {
+        keep_newest(data_source, table, max_rows, conn)?;
+    }
+
+    Ok(())
+}
+
+// the column data_retention_days is measured against - retention_column if it's set, otherwise the
+// timestamp column as long as it really holds timestamps rather than an index
+fn age_column<'a>(
+    data_source: &'a DataSourceConfiguration,
+    conn: &duckdb::Connection,
+) -> Result<Option<&'a str>, LoaderError> {
+    let name = data_source
+        .retention_column
+        .as_deref()
+        .unwrap_or(&data_source.timestamp_column_name);
+
+    let column = table_columns(conn, &data_source.table_name)?
+        .into_iter()
+        .find(|c| c.name == name);
+
+    Ok(match column {
+        Some(c) if c.data_type.starts_with("TIMESTAMP") || c.data_type == "DATE" => Some(name),
+        // only worth mentioning if it was set on purpose
+        Some(c) if data_source.retention_column.is_some() => {
+            warn!(
+                "retention_column {name} of table {} is {}, not a timestamp or date, so data_retention_days is ignored",
+                data_source.table_name, c.data_type
+            );
+            None
+        }
+        None if data_source.retention_column.is_some() => {
+            warn!(
+                "retention_column {name} isn't in table {}, so data_retention_days is ignored",
+                data_source.table_name
+            );
+            None
+        }
+        _ => None,
+    })
+}
+
+// removes all but the newest max_rows rows, newest going by the timestamp column and secondary index
+// - the same order watermarks are taken in
+fn keep_newest(
+    data_source: &DataSourceConfiguration,
+    table: TableName,
+    max_rows: u64,
+    conn: &duckdb::Connection,
+) -> Result<(), LoaderError> {
+    let mut order = vec![format!(
+        "{} DESC NULLS LAST",
+        quote_identifier(&data_source.timestamp_column_name)
+    )];
+
+    if let Some(secondary_index) = &data_source.secondary_index {
+        order.push(format!(
+            "{} DESC NULLS LAST",
+            quote_identifier(secondary_index)
+        ));
+    }
+
+    let removed = conn.execute(
+        format!(
+            "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} ORDER BY {} OFFSET {max_rows})",
+            order.join(", ")
+        )
+        .as_str(),
+        [],
+    )?;
+
+    if removed > 0 {
+        debug!("removed the {removed} oldest rows from table {table} to keep it within its limits");
+    }
+
+    Ok(())
+}
+
+// how many rows fit in max_bytes, going by how much space the rows already written to the database
+// file take up. Rows only written to memory so far (and in-memory databases) can't be measured, in
+// which case there's no limit until they are. Deleted rows keep their space until duckdb reclaims it,
+// so they're counted too - otherwise each run would trim the table a little further
+fn rows_within_size(
+    table: TableName,
+    max_bytes: u64,
+    conn: &duckdb::Connection,
+) -> Result<Option<u64>, LoaderError> {
+    let (blocks, stored_rows): (i64, Option<i64>) = conn.query_row(
+        format!(
+            "SELECT count(DISTINCT block_id), sum(count) FILTER (WHERE column_id = 0 AND segment_type <> 'VALIDITY')
+             FROM pragma_storage_info({}) WHERE persistent",
+            quote_literal(&table.to_string())
+        )
+        .as_str(),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let stored_rows = stored_rows.unwrap_or(0);
+    if blocks <= 0 || stored_rows <= 0 {
+        return Ok(None);
+    }
+
+    let stored_bytes = blocks as u128 * BLOCK_SIZE;
+    let rows = max_bytes as u128 * stored_rows as u128 / stored_bytes;
+
+    Ok(Some(u64::try_from(rows).unwrap_or(u64::MAX)))
+}
+
+// parses sizes like 500MB, 2GB or a plain number of bytes - units are powers of 1024
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let unit_at = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let amount: u64 = size[..unit_at].parse().ok()?;
+
+    let unit = match size[unit_at..].trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "KIB" => 1 << 10,
+        "MB" | "MIB" => 1 << 20,
+        "GB" | "GIB" => 1 << 30,
+        "TB" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+
+    amount.checked_mul(unit).filter(|bytes| *bytes > 0)
+}
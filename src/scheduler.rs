@@ -0,0 +1,306 @@
+use crate::deep_lynx::DeepLynxAPI;
+use crate::errors::LoaderError;
+use crate::report::{SourceFailure, Stage};
+use crate::{
+    close_connection, download, load_download, max_concurrency, open_connection, plan_fetch,
+    Configuration, DataSourceConfiguration, Download, FetchPlan,
+};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use log::{debug, error};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// handle to the background refresh thread. The thread waits on the receiving end of the stop channel
+// between runs, so sending on it (or dropping the sender along with the Loader) wakes the thread
+// immediately instead of waiting out the rest of the refresh interval. A fetch that is already in
+// flight is always allowed to finish.
+#[derive(Debug)]
+pub struct Scheduler {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+// how often a single data source should be fetched - sources with a cron schedule take precedence
+// over an interval, and sources with neither fall back to the global refresh_interval
+#[derive(Debug, Clone)]
+enum Cadence {
+    Interval(chrono::Duration),
+    Cron(Box<Schedule>),
+}
+
+impl Cadence {
+    fn new(
+        config: &Configuration,
+        data_source: &DataSourceConfiguration,
+    ) -> Result<Cadence, LoaderError> {
+        if let Some(schedule) = &data_source.schedule {
+            return Ok(Cadence::Cron(Box::new(Schedule::from_str(schedule)?)));
+        }
+
+        let seconds = data_source
+            .refresh_interval
+            .unwrap_or(config.refresh_interval);
+
+        Ok(Cadence::Interval(chrono::Duration::seconds(seconds as i64)))
+    }
+
+    fn next_run(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Cadence::Interval(interval) => after + *interval,
+            // a schedule with no upcoming times will never fire again, push it out as far as we can
+            Cadence::Cron(schedule) => schedule
+                .after(&after)
+                .next()
+                .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC),
+        }
+    }
+}
+
+impl Scheduler {
+    // client is cloned for each run, the clones all share the same token
+    pub fn start(config: Configuration, client: DeepLynxAPI) -> Result<Scheduler, LoaderError> {
+        // parse all the cadences up front so that a bad cron expression fails start() instead of
+        // silently never running
+        let cadences = config
+            .data_sources
+            .iter()
+            .map(|data_source| Cadence::new(&config, data_source))
+            .collect::<Result<Vec<Cadence>, LoaderError>>()?;
+
+        let (stop, stopped) = channel::<()>();
+
+        let handle = thread::Builder::new()
+            .name("deeplynx-loader".to_string())
+            .spawn(move || {
+                let config = Arc::new(config);
+                // all data sources share a single database, so only one of them can be using it at a
+                // time - downloads don't need the database and are bounded separately
+                let db_lock = Arc::new(Mutex::new(()));
+                let permits = Arc::new(Permits::new(max_concurrency(&config)));
+                // tells backfilling sources to stop after their current window
+                let stopping = Arc::new(AtomicBool::new(false));
+
+                // everything runs immediately on start, then on its own cadence after that
+                let mut next_runs: Vec<DateTime<Utc>> = vec![Utc::now(); cadences.len()];
+                let mut jobs: Vec<Option<JoinHandle<()>>> =
+                    (0..cadences.len()).map(|_| None).collect();
+
+                loop {
+                    let now = Utc::now();
+
+                    for (i, cadence) in cadences.iter().enumerate() {
+                        if next_runs[i] > now {
+                            continue;
+                        }
+
+                        next_runs[i] = cadence.next_run(now);
+
+                        // each source runs on its own thread so a slow source doesn't hold up the
+                        // others, but we never run the same source twice at once
+                        let in_flight = jobs[i]
+                            .as_ref()
+                            .map(|job| !job.is_finished())
+                            .unwrap_or(false);
+
+                        if in_flight {
+                            debug!(
+                                "table {} is still loading, skipping this run",
+                                config.data_sources[i].table_name
+                            );
+                            continue;
+                        }
+
+                        let config = config.clone();
+                        let client = client.clone();
+                        let db_lock = db_lock.clone();
+                        let permits = permits.clone();
+                        let stopping = stopping.clone();
+
+                        let job = thread::Builder::new()
+                            .name(format!(
+                                "deeplynx-loader-{}",
+                                config.data_sources[i].table_name
+                            ))
+                            .spawn(move || {
+                                let data_source = &config.data_sources[i];
+
+                                // errors are logged rather than returned so that a single bad fetch
+                                // doesn't kill the background thread, the next run will try again
+                                if let Err(failure) = run_source(
+                                    &config,
+                                    &client,
+                                    data_source,
+                                    &db_lock,
+                                    &permits,
+                                    &stopping,
+                                ) {
+                                    error!("background load failed: {failure}");
+                                }
+                            });
+
+                        match job {
+                            Ok(job) => jobs[i] = Some(job),
+                            Err(e) => error!("unable to spawn loader thread: {e}"),
+                        }
+                    }
+
+                    // sleep until the next source is due or we're told to stop
+                    let wait = next_runs
+                        .iter()
+                        .min()
+                        .map(|next| (*next - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+                        .unwrap_or(Duration::MAX);
+
+                    match stopped.recv_timeout(wait) {
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        // either we were told to stop or the loader has gone away
+                        _ => break,
+                    }
+                }
+
+                // let any in-flight fetches finish before we report that we've stopped
+                stopping.store(true, Ordering::SeqCst);
+                for job in jobs.into_iter().flatten() {
+                    let _ = job.join();
+                }
+
+                debug!("background refresh thread stopped");
+            })?;
+
+        Ok(Scheduler { stop, handle })
+    }
+
+    // signals the background thread and blocks until it exits, waiting on any in-flight fetch
+    pub fn stop(self) -> Result<(), LoaderError> {
+        // the thread might have already exited and dropped the receiver, that's fine
+        let _ = self.stop.send(());
+
+        self.handle
+            .join()
+            .map_err(|_| LoaderError::SchedulerPanicked)
+    }
+}
+
+// loads a single data source, only holding the database lock while reading or writing to it so that
+// other data sources can use the database while this one is downloading
+fn run_source(
+    config: &Configuration,
+    client: &DeepLynxAPI,
+    data_source: &DataSourceConfiguration,
+    db_lock: &Mutex<()>,
+    permits: &Permits,
+    stopping: &AtomicBool,
+) -> Result<(), SourceFailure> {
+    let failed =
+        move |stage: Stage| move |e: LoaderError| SourceFailure::new(data_source, stage, e);
+
+    let mut plan = with_connection(config, db_lock, |conn| plan_fetch(data_source, conn))
+        .map_err(failed(Stage::Plan))?;
+
+    loop {
+        let downloaded = download_with_permit(config, client, data_source, &plan, permits)
+            .map_err(failed(Stage::Download))?;
+
+        with_connection(config, db_lock, |conn| {
+            load_download(config, data_source, &plan, downloaded, conn)
+        })
+        .map_err(failed(Stage::Load))?;
+
+        if !matches!(plan, FetchPlan::Backfill(..)) {
+            return Ok(());
+        }
+
+        // keep working through a backfill until it catches up, unless we've been asked to stop - the
+        // progress is saved with each window so the next start picks up where we left off
+        let next = with_connection(config, db_lock, |conn| plan_fetch(data_source, conn))
+            .map_err(failed(Stage::Plan))?;
+
+        if !next.continues(&plan) || stopping.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        plan = next;
+    }
+}
+
+// waits for a free download slot before downloading, so only max_concurrency downloads run at once
+fn download_with_permit(
+    config: &Configuration,
+    client: &DeepLynxAPI,
+    data_source: &DataSourceConfiguration,
+    plan: &FetchPlan,
+    permits: &Permits,
+) -> Result<Download, LoaderError> {
+    let _permit = permits.acquire()?;
+
+    download(config, data_source, client, plan.query())
+}
+
+// opens a connection for the duration of f while holding the database lock
+fn with_connection<T>(
+    config: &Configuration,
+    db_lock: &Mutex<()>,
+    f: impl FnOnce(&duckdb::Connection) -> Result<T, LoaderError>,
+) -> Result<T, LoaderError> {
+    let _guard = db_lock.lock().map_err(|_| LoaderError::SchedulerPanicked)?;
+
+    let conn = open_connection(config)?;
+    let result = f(&conn);
+    close_connection(conn)?;
+
+    result
+}
+
+// counting semaphore bounding how many data sources can be downloading at once
+#[derive(Debug)]
+struct Permits {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// returned by Permits::acquire, hands the permit back when dropped
+struct Permit<'a> {
+    permits: &'a Permits,
+}
+
+impl Permits {
+    fn new(count: usize) -> Permits {
+        Permits {
+            available: Mutex::new(count),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Result<Permit<'_>, LoaderError> {
+        let mut available = self
+            .available
+            .lock()
+            .map_err(|_| LoaderError::SchedulerPanicked)?;
+
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .map_err(|_| LoaderError::SchedulerPanicked)?;
+        }
+
+        *available -= 1;
+        Ok(Permit { permits: self })
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut available) = self.permits.available.lock() {
+            *available += 1;
+        }
+
+        self.permits.released.notify_one();
+    }
+}
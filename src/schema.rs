@@ -0,0 +1,193 @@
+use crate::errors::LoaderError;
+use crate::sql::{quote_identifier, TableName};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+// what to do when the columns of a download don't match the table we're loading it into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaChangePolicy {
+    // add new columns to the table, keep removed columns (they'll be null going forward) and cast
+    // retyped columns to the table's existing type
+    #[default]
+    Add,
+    // load only the columns the table already has, casting retyped columns to the existing type
+    Ignore,
+    // refuse to load the download at all
+    Fail,
+    // drop the table and start over from the initial fetch - this throws away local history
+    Rebuild,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub data_type: String,
+}
+
+// the differences between the columns of an existing table and an incoming download
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    pub added: Vec<Column>,
+    pub removed: Vec<Column>,
+    // (existing, incoming)
+    pub retyped: Vec<(Column, Column)>,
+}
+
+impl SchemaDiff {
+    pub fn new(existing: &[Column], incoming: &[Column]) -> SchemaDiff {
+        let mut diff = SchemaDiff::default();
+
+        for column in incoming {
+            match existing.iter().find(|e| e.name == column.name) {
+                None => diff.added.push(column.clone()),
+                Some(e) if e.data_type != column.data_type => {
+                    diff.retyped.push((e.clone(), column.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for column in existing {
+            if !incoming.iter().any(|i| i.name == column.name) {
+                diff.removed.push(column.clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.retyped.is_empty()
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let added: Vec<String> = self
+            .added
+            .iter()
+            .map(|c| format!("{} {}", c.name, c.data_type))
+            .collect();
+        let removed: Vec<&str> = self.removed.iter().map(|c| c.name.as_str()).collect();
+        let retyped: Vec<String> = self
+            .retyped
+            .iter()
+            .map(|(e, i)| format!("{} {} -> {}", e.name, e.data_type, i.data_type))
+            .collect();
+
+        write!(
+            f,
+            "added [{}], removed [{}], retyped [{}]",
+            added.join(", "),
+            removed.join(", "),
+            retyped.join(", ")
+        )
+    }
+}
+
+// how a download should be loaded once the table's schema has been reconciled with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reconciled {
+    // insert into these table columns, selecting the matching expressions from the download
+    Insert {
+        columns: Vec<String>,
+        select: Vec<String>,
+    },
+    // the table needs to be dropped and loaded from scratch
+    Rebuild,
+}
+
+pub fn table_columns(
+    conn: &duckdb::Connection,
+    table_name: &str,
+) -> Result<Vec<Column>, LoaderError> {
+    let table = TableName::parse(table_name);
+    let mut statement = conn.prepare(
+        "SELECT column_name, data_type FROM duckdb_columns()
+         WHERE table_name = ? AND schema_name = coalesce(?, schema_name)
+         ORDER BY column_index",
+    )?;
+
+    let columns = statement
+        .query_map(duckdb::params![table.name, table.schema], |row| {
+            Ok(Column {
+                name: row.get(0)?,
+                data_type: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<Column>, duckdb::Error>>()?;
+
+    Ok(columns)
+}
+
+// compares the table with the staged download and alters the table to match according to the policy
+pub fn reconcile(
+    conn: &duckdb::Connection,
+    table_name: &str,
+    staging_table: &str,
+    policy: SchemaChangePolicy,
+) -> Result<Reconciled, LoaderError> {
+    let existing = table_columns(conn, table_name)?;
+    let incoming = table_columns(conn, staging_table)?;
+    let diff = SchemaDiff::new(&existing, &incoming);
+
+    if !diff.is_empty() {
+        debug!("schema of table {table_name} differs from download: {diff}");
+
+        match policy {
+            SchemaChangePolicy::Fail => {
+                return Err(LoaderError::SchemaChanged(
+                    table_name.to_string(),
+                    diff.to_string(),
+                ))
+            }
+            SchemaChangePolicy::Rebuild => {
+                warn!("schema of table {table_name} changed ({diff}), rebuilding table");
+                return Ok(Reconciled::Rebuild);
+            }
+            SchemaChangePolicy::Add => {
+                for column in &diff.added {
+                    conn.execute(
+                        format!(
+                            "ALTER TABLE {} ADD COLUMN {} {}",
+                            TableName::parse(table_name),
+                            quote_identifier(&column.name),
+                            column.data_type
+                        )
+                        .as_str(),
+                        [],
+                    )?;
+                }
+            }
+            SchemaChangePolicy::Ignore => {}
+        }
+    }
+
+    // only load the incoming columns the table now has, casting anything whose type changed to what
+    // the table already holds - columns the download is missing are left null
+    let mut columns = vec![];
+    let mut select = vec![];
+
+    for column in &incoming {
+        let target = match existing.iter().find(|e| e.name == column.name) {
+            Some(e) => e,
+            None if policy == SchemaChangePolicy::Add => column,
+            None => continue,
+        };
+
+        columns.push(quote_identifier(&column.name));
+
+        if target.data_type == column.data_type {
+            select.push(quote_identifier(&column.name));
+        } else {
+            select.push(format!(
+                "CAST({} AS {})",
+                quote_identifier(&column.name),
+                target.data_type
+            ));
+        }
+    }
+
+    Ok(Reconciled::Insert { columns, select })
+}
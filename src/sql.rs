@@ -0,0 +1,69 @@
+use std::fmt;
+
+// quotes an identifier so that spaces, mixed case, reserved words and even quotes in it can't break
+// (or inject into) the sql it ends up in
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// quotes a string literal, for the places duckdb wants a name or path as a string rather than an
+// identifier
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// once quoted a table or column name can be almost anything, so this only catches names in the
+// configuration that are almost certainly mistakes
+pub fn check_identifier(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+
+    if name.trim() != name {
+        return Err(format!("{name:?} must not start or end with whitespace"));
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(format!("{name:?} must not contain control characters"));
+    }
+
+    Ok(())
+}
+
+// a table we read or write, either a plain name or qualified with the schema it's in like
+// plant_a.sensor_readings. Plain names are left to duckdb to resolve, which also finds temp tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableName<'a> {
+    pub schema: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl<'a> TableName<'a> {
+    pub fn parse(table_name: &'a str) -> TableName<'a> {
+        match table_name.split_once('.') {
+            None => TableName {
+                schema: None,
+                name: table_name,
+            },
+            Some((schema, name)) => TableName {
+                schema: Some(schema),
+                name,
+            },
+        }
+    }
+}
+
+// the quoted form, ready to go into sql
+impl fmt::Display for TableName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.schema {
+            None => write!(f, "{}", quote_identifier(self.name)),
+            Some(schema) => write!(
+                f,
+                "{}.{}",
+                quote_identifier(schema),
+                quote_identifier(self.name)
+            ),
+        }
+    }
+}
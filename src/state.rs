@@ -0,0 +1,149 @@
+use crate::errors::LoaderError;
+use crate::DataSourceConfiguration;
+use duckdb::{params, OptionalExt};
+
+// internal table recording where each data source left off. We used to infer this from the last row
+// of the user's table, but users are free to delete from it and clean_data can empty it entirely -
+// neither of which should change where we resume from
+pub const STATE_TABLE: &str = "_deeplynx_loader_state";
+
+// the last record loaded for a data source, in the form the DeepLynx download api expects
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+    pub timestamp: Option<String>, // the api expects a string even if it's an index number
+    pub secondary_index: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceState {
+    pub watermark: Watermark,
+    pub last_file_id: Option<String>,
+    // where the next backfill window starts, None once the backfill has caught up (or if the data
+    // source isn't backfilled in windows)
+    pub backfill_until: Option<String>,
+}
+
+pub fn ensure_state_table(conn: &duckdb::Connection) -> Result<(), LoaderError> {
+    conn.execute_batch(
+        format!(
+            "CREATE TABLE IF NOT EXISTS {STATE_TABLE} (
+                table_name VARCHAR PRIMARY KEY,
+                data_source_id UBIGINT,
+                last_timestamp VARCHAR,
+                last_secondary_index UBIGINT,
+                last_file_id VARCHAR,
+                loaded_at TIMESTAMP
+            );
+            ALTER TABLE {STATE_TABLE} ADD COLUMN IF NOT EXISTS backfill_until VARCHAR;"
+        )
+        .as_str(),
+    )?;
+
+    Ok(())
+}
+
+pub fn load_state(
+    conn: &duckdb::Connection,
+    table_name: &str,
+) -> Result<Option<SourceState>, LoaderError> {
+    ensure_state_table(conn)?;
+
+    let state = conn
+        .query_row(
+            format!(
+                "SELECT last_timestamp, last_secondary_index, last_file_id, backfill_until FROM {STATE_TABLE} WHERE table_name = ?"
+            )
+            .as_str(),
+            [table_name],
+            |row| {
+                Ok(SourceState {
+                    watermark: Watermark {
+                        timestamp: row.get(0)?,
+                        secondary_index: row.get(1)?,
+                    },
+                    last_file_id: row.get(2)?,
+                    backfill_until: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(state)
+}
+
+// records a completed load - if the download was empty there's no new watermark, so we keep the
+// previous one and only note that the load happened. Backfill progress is left as it was
+pub fn save_state(
+    conn: &duckdb::Connection,
+    data_source: &DataSourceConfiguration,
+    watermark: Option<&Watermark>,
+    file_id: &str,
+) -> Result<(), LoaderError> {
+    ensure_state_table(conn)?;
+
+    match watermark {
+        None => conn.execute(
+            format!(
+                "UPDATE {STATE_TABLE} SET last_file_id = ?, loaded_at = now() WHERE table_name = ?"
+            )
+            .as_str(),
+            params![file_id, data_source.table_name],
+        )?,
+        Some(w) => conn.execute(
+            format!(
+                "INSERT OR REPLACE INTO {STATE_TABLE} SELECT ?, ?, ?, ?, ?, now(),
+                    (SELECT backfill_until FROM {STATE_TABLE} WHERE table_name = ?)"
+            )
+            .as_str(),
+            params![
+                data_source.table_name,
+                data_source.data_source_id,
+                w.timestamp,
+                w.secondary_index,
+                file_id,
+                data_source.table_name
+            ],
+        )?,
+    };
+
+    Ok(())
+}
+
+// records where the next backfill window starts, or that the backfill is finished if until is None.
+// A data source can make progress before anything has been loaded (its first windows were empty), so
+// this creates the state entry if there isn't one
+pub fn save_backfill_progress(
+    conn: &duckdb::Connection,
+    data_source: &DataSourceConfiguration,
+    until: Option<&str>,
+) -> Result<(), LoaderError> {
+    ensure_state_table(conn)?;
+
+    let updated = conn.execute(
+        format!("UPDATE {STATE_TABLE} SET backfill_until = ? WHERE table_name = ?").as_str(),
+        params![until, data_source.table_name],
+    )?;
+
+    if updated == 0 {
+        conn.execute(
+            format!(
+                "INSERT INTO {STATE_TABLE} (table_name, data_source_id, backfill_until) VALUES (?, ?, ?)"
+            )
+            .as_str(),
+            params![data_source.table_name, data_source.data_source_id, until],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn clear_state(conn: &duckdb::Connection, table_name: &str) -> Result<(), LoaderError> {
+    ensure_state_table(conn)?;
+
+    conn.execute(
+        format!("DELETE FROM {STATE_TABLE} WHERE table_name = ?").as_str(),
+        [table_name],
+    )?;
+
+    Ok(())
+}
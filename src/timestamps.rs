@@ -0,0 +1,186 @@
+use crate::errors::LoaderError;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Offset, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use duckdb::types::TimeUnit;
+use std::str::FromStr;
+
+// how far back the initial load of a data source goes if it doesn't set initial_timestamp
+const DEFAULT_INITIAL_LOOKBACK_DAYS: i64 = 1;
+
+// formats a timestamp the way we send them to DeepLynx
+pub fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+// the timezone of a data source's TIMESTAMP (without time zone) columns, UTC unless configured
+pub fn parse_timezone(timezone: Option<&str>) -> Result<Tz, LoaderError> {
+    let timezone = timezone.map(str::trim).unwrap_or("UTC");
+
+    Tz::from_str(timezone).map_err(|_| LoaderError::InvalidTimezone(timezone.to_string()))
+}
+
+// duckdb hands TIMESTAMP and TIME values back as an offset from the epoch in the column's unit
+fn from_epoch(unit: TimeUnit, value: i64) -> Option<NaiveDateTime> {
+    let (per_second, nanos_per_unit) = match unit {
+        TimeUnit::Second => (1, 1_000_000_000),
+        TimeUnit::Millisecond => (1_000, 1_000_000),
+        TimeUnit::Microsecond => (1_000_000, 1_000),
+        TimeUnit::Nanosecond => (1_000_000_000, 1),
+    };
+
+    NaiveDateTime::from_timestamp_opt(
+        value.div_euclid(per_second),
+        (value.rem_euclid(per_second) * nanos_per_unit) as u32,
+    )
+}
+
+// formats a timestamp watermark as RFC 3339 without losing any precision. TIMESTAMP WITH TIME ZONE
+// values are instants so they go out in UTC, but plain TIMESTAMP values are wall clock times - those
+// keep their wall clock time and are given the offset of the data source's timezone, so DeepLynx
+// compares them correctly whichever kind of column it has
+pub fn format_watermark_timestamp(
+    unit: TimeUnit,
+    value: i64,
+    with_time_zone: bool,
+    timezone: Tz,
+) -> Option<String> {
+    let timestamp = from_epoch(unit, value)?;
+
+    if with_time_zone {
+        return Some(format_timestamp(Utc.from_utc_datetime(&timestamp)));
+    }
+
+    // wall clock times skipped by a daylight saving change can't really have been recorded, but if
+    // one turns up give it the offset in effect at that moment in UTC
+    let offset = match timezone.offset_from_local_datetime(&timestamp).earliest() {
+        Some(offset) => offset.fix(),
+        None => timezone.offset_from_utc_datetime(&timestamp).fix(),
+    };
+
+    offset
+        .from_local_datetime(&timestamp)
+        .single()
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+// formats a TIME watermark, a time of day rather than a timestamp
+pub fn format_watermark_time(unit: TimeUnit, value: i64) -> Option<String> {
+    from_epoch(unit, value).map(|t| t.time().format("%H:%M:%S%.f").to_string())
+}
+
+// formats a DATE watermark, which duckdb hands back as days since the epoch
+pub fn format_watermark_date(days: i32) -> Option<String> {
+    NaiveDate::from_ymd_opt(1970, 1, 1)?
+        .checked_add_signed(Duration::days(days as i64))
+        .map(|d| d.format("%Y-%m-%d").to_string())
+}
+
+// works out the start_time of a data source's initial load from its initial_timestamp, one day
+// before now if it isn't set
+pub fn initial_start_time(
+    initial_timestamp: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<String, LoaderError> {
+    match initial_timestamp.map(str::trim) {
+        None | Some("") => Ok(format_timestamp(
+            now - Duration::days(DEFAULT_INITIAL_LOOKBACK_DAYS),
+        )),
+        Some(t) => resolve_timestamp(t, now),
+    }
+}
+
+// works out a timestamp given in the configuration or to backfill, which can be
+//  - relative to now, e.g -7d, -12h, -30m, -45s or -2w
+//  - an absolute timestamp (2024-01-01T00:00:00Z, 2024-01-01 00:00:00 or 2024-01-01), passed through
+//    as written once we're sure it parses
+//  - a plain number, for data sources whose primary column is an index rather than a timestamp
+pub fn resolve_timestamp(timestamp: &str, now: DateTime<Utc>) -> Result<String, LoaderError> {
+    let timestamp = timestamp.trim();
+
+    if let Some(ago) = timestamp.strip_prefix('-') {
+        return duration(ago)
+            .and_then(|ago| now.checked_sub_signed(ago))
+            .map(format_timestamp)
+            .ok_or_else(|| LoaderError::InvalidTimestamp(timestamp.to_string()));
+    }
+
+    if is_index(timestamp) || parse_timestamp(timestamp).is_some() {
+        return Ok(timestamp.to_string());
+    }
+
+    Err(LoaderError::InvalidTimestamp(timestamp.to_string()))
+}
+
+// true for the start values of data sources whose primary column is an index rather than a timestamp
+pub fn is_index(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())
+}
+
+// parses the absolute timestamp formats we accept, naive timestamps being taken as UTC
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.with_timezone(&Utc));
+    }
+
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+// how much of a data source's history is fetched at once when backfilling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackfillWindow {
+    // for timestamp columns, e.g 1d or 6h
+    Time(Duration),
+    // for index columns, a number of index values
+    Index(u64),
+}
+
+impl FromStr for BackfillWindow {
+    type Err = LoaderError;
+
+    fn from_str(window: &str) -> Result<BackfillWindow, LoaderError> {
+        let window = window.trim();
+
+        let parsed = if is_index(window) {
+            window.parse().ok().map(BackfillWindow::Index)
+        } else {
+            duration(window).map(BackfillWindow::Time)
+        };
+
+        match parsed {
+            Some(BackfillWindow::Index(size)) if size > 0 => Ok(BackfillWindow::Index(size)),
+            Some(BackfillWindow::Time(size)) if size > Duration::zero() => {
+                Ok(BackfillWindow::Time(size))
+            }
+            _ => Err(LoaderError::InvalidBackfillWindow(window.to_string())),
+        }
+    }
+}
+
+// parses durations like 7d, 12h, 30m, 45s or 2w
+fn duration(value: &str) -> Option<Duration> {
+    let unit_at = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..unit_at].parse().ok()?;
+
+    let unit_seconds = match &value[unit_at..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    // chrono panics on durations it can't represent, so catch absurd values here
+    amount
+        .checked_mul(unit_seconds)
+        .filter(|seconds| *seconds <= i64::MAX / 1000)
+        .map(Duration::seconds)
+}